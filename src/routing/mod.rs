@@ -2,8 +2,8 @@ use hash::HASH_SIZE;
 use hash::Hash;
 use std::net;
 use std::collections::VecDeque;
-use std::mem;
 use std::sync::{Mutex, RwLock};
+use time;
 
 #[cfg(test)]
 mod tests;
@@ -12,15 +12,27 @@ pub const ALPHA    : usize = 3;
 pub const K        : usize = 20;
 const BUCKET_DEPTH : usize = K;
 
+/// Nodes seen more recently than this are candidates for the "reliable" tier,
+/// provided their success ratio also clears `RELIABILITY_SUCCESS_RATIO`.
+const RELIABILITY_RECENCY_MINS  : i64 = 15;
+
+/// Minimum ratio of successful to total RPCs a node needs (once it has any
+/// RPC history at all) to still count as reliable.
+const RELIABILITY_SUCCESS_RATIO : f64 = 0.8;
+
+/// How long a newcomer may wait in a bucket's pending slot for the RPC layer
+/// to ping the least-recently-seen node it would replace.
+const PENDING_REPLACEMENT_TIMEOUT_SECS : i64 = 5;
+
 /// Kademlia routing table, with 160 buckets of `BUCKET_DEPTH` (k) node
 /// identifiers each, constructed around a parent node ID.
 ///
-/// The structure employs least-recently seen eviction. Conflicts generated
-/// by evicting a node by inserting a newer one remain tracked, so they can
-/// be resolved later.
+/// The structure employs least-recently seen eviction, softened by a
+/// pending replacement cache: a newcomer arriving at a full bucket doesn't
+/// evict anyone outright, but waits while the least-recently-seen node is
+/// given a chance to prove it's still alive.
 pub struct Table {
    buckets   : Vec<Bucket>,
-   conflicts : Mutex<Vec<EvictionConflict>>,
    parent_id : Hash,
 }
 
@@ -33,7 +45,7 @@ pub struct NodeInfo {
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 pub enum LookupResult {
    Myself,
-   Found(NodeInfo), 
+   Found(NodeInfo),
    ClosestNodes(Vec<NodeInfo>),
    Nothing,
 }
@@ -42,31 +54,97 @@ impl Table {
    /// Constructs a routing table based on a parent node id. Other nodes
    /// will be stored in this table based on their distance to the node id provided.
    pub fn new(parent_id: Hash) -> Table {
-      Table { 
+      Table {
          buckets   : (0..HASH_SIZE).map(|_| Bucket::new()).collect(),
-         conflicts : Mutex::new(Vec::new()),
          parent_id : parent_id,
       }
    }
 
-   /// Inserts a node in the routing table. Employs least-recently-seen eviction
-   /// by kicking out the oldest node in case the bucket is full, and registering
-   /// an eviction conflict that can be revised later.
+   /// Inserts a node in the routing table. If the node's bucket still has
+   /// room, it's added outright. If the bucket is full, the newcomer is
+   /// held in a pending slot rather than evicting anyone immediately; call
+   /// `challenge_for` to learn who to ping, and feed the outcome back
+   /// through `confirm_least_recently_seen` or `apply_expired_pending_insertions`.
    pub fn insert_node(&self, info: NodeInfo) {
       if let Some(index) = self.bucket_for_node(&info.id) {
          let bucket = &self.buckets[index];
+         // Lock order is always `pending` before `entries`, to match
+         // `apply_expired_pending_insertions` and avoid an AB-BA deadlock
+         // between the two buckets locks.
+         let mut pending = bucket.pending.lock().unwrap();
          let mut entries = bucket.entries.write().unwrap();
 
-         entries.retain(|ref stored_info| info.id != stored_info.id);
+         entries.retain(|ref entry| info.id != entry.info.id);
          if entries.len() == BUCKET_DEPTH {
-            let conflict = EvictionConflict { 
-               evicted  : entries.pop_front().unwrap(),
-               inserted : info.clone() 
-            };
-            let mut conflicts = self.conflicts.lock().unwrap();
-            conflicts.push(conflict);
+            let deadline = time::SteadyTime::now() + time::Duration::seconds(PENDING_REPLACEMENT_TIMEOUT_SECS);
+            *pending = Some((info, deadline));
+         } else {
+            entries.push_back(BucketEntry::new(info));
+         }
+      }
+   }
+
+   /// If `id`'s bucket is full and has a newcomer waiting in its pending
+   /// slot, returns the least-recently-seen node that should be pinged to
+   /// decide the newcomer's fate. Intended to be driven by the node's RPC
+   /// layer whenever `insert_node` leaves a bucket with a pending entry.
+   pub fn challenge_for(&self, id: &Hash) -> Option<NodeInfo> {
+      match self.bucket_for_node(id) {
+         Some(index) => {
+            let bucket = &self.buckets[index];
+            // `pending` is released before `entries` is locked, keeping
+            // with the bucket-wide `pending` before `entries` order.
+            let has_pending = bucket.pending.lock().unwrap().is_some();
+            if !has_pending {
+               return None;
+            }
+            bucket.entries.read().unwrap().front().map(|entry| entry.info.clone())
+         },
+         None => None,
+      }
+   }
+
+   /// Called by the RPC layer when the least-recently-seen node of a bucket
+   /// answers its challenge ping before the deadline: the responder is
+   /// refreshed and moved to the most-recently-seen position, and the
+   /// newcomer waiting in the pending slot is discarded.
+   pub fn confirm_least_recently_seen(&self, id: &Hash) {
+      if let Some(index) = self.bucket_for_node(id) {
+         let bucket = &self.buckets[index];
+         // Same `pending` before `entries` lock order as `insert_node`.
+         let mut pending = bucket.pending.lock().unwrap();
+         let mut entries = bucket.entries.write().unwrap();
+         if let Some(position) = entries.iter().position(|entry| entry.info.id == *id) {
+            let mut entry = entries.remove(position).unwrap();
+            entry.liveness.last_seen = time::SteadyTime::now();
+            entry.liveness.successes += 1;
+            entries.push_back(entry);
+         }
+         *pending = None;
+      }
+   }
+
+   /// Sweeps every bucket for pending replacements whose deadline has
+   /// passed, evicting the unresponsive least-recently-seen node and
+   /// promoting the pending newcomer into its place. Meant to be run
+   /// periodically, so a node that simply never answers its challenge ping
+   /// still gets replaced.
+   pub fn apply_expired_pending_insertions(&self) {
+      let now = time::SteadyTime::now();
+      for bucket in &self.buckets {
+         let mut pending = bucket.pending.lock().unwrap();
+         let expired = match *pending {
+            Some((_, deadline)) => now > deadline,
+            None => false,
+         };
+
+         if expired {
+            if let Some((newcomer, _)) = pending.take() {
+               let mut entries = bucket.entries.write().unwrap();
+               entries.pop_front();
+               entries.push_back(BucketEntry::new(newcomer));
+            }
          }
-         entries.push_back(info);
       }
    }
 
@@ -75,11 +153,11 @@ impl Table {
    /// report that the parent node itself was requested.
    ///
    /// This employs an algorithm I have named "bounce lookup", which obtains
-   /// the closest nodes to a given origin walking through the minimum 
-   /// amount of buckets. It may exist already, but I haven't 
+   /// the closest nodes to a given origin walking through the minimum
+   /// amount of buckets. It may exist already, but I haven't
    /// found it any other implementation. It consists of:
    ///
-   /// * Calculating the XOR distance between the parent node ID and the 
+   /// * Calculating the XOR distance between the parent node ID and the
    ///   lookup node ID.
    ///
    /// * Checking the buckets indexed by the position of every "1" in said
@@ -108,8 +186,8 @@ impl Table {
    /// Returns an iterator over all stored nodes, ordered by ascending
    /// distance to the parent node. This iterator is designed for concurrent
    /// access to the data structure, and as such it isn't guaranteed that it
-   /// will return a "snapshot" of all nodes for a specific moment in time. 
-   /// Buckets already visited may be modified elsewhere through iteraton, 
+   /// will return a "snapshot" of all nodes for a specific moment in time.
+   /// Buckets already visited may be modified elsewhere through iteraton,
    /// and unvisited buckets may accrue new nodes.
    pub fn all_nodes(&self) -> AllNodes {
       AllNodes {
@@ -123,26 +201,25 @@ impl Table {
    pub fn specific_node(&self, id: &Hash) -> Option<NodeInfo> {
       if let Some(index) = self.bucket_for_node(id) {
          let entries = &self.buckets[index].entries.read().unwrap();
-         return entries.iter().find(|ref info| *id == info.id).cloned();
+         return entries.iter().find(|ref entry| *id == entry.info.id).map(|entry| entry.info.clone());
       }
       None
    }
 
-   /// Bounce lookup algorithm.
+   /// Bounce lookup algorithm, sorting purely by XOR distance. Kept around
+   /// for callers that don't care about liveness, and as the fallback
+   /// ordering used within each reliability tier of
+   /// `find_preferred_closest_nodes`.
    fn closest_n_nodes_to(&self, id: &Hash, n: usize, blacklist: Option<&Vec<Hash>>) -> Vec<NodeInfo> {
       let mut closest = Vec::with_capacity(n);
-      let distance = &self.parent_id ^ id;
-      let descent  = distance.ones().rev();
-      let ascent   = distance.zeroes();
-      let lookup_order = descent.chain(ascent);
-      
-      for bucket_index in lookup_order {
+
+      for bucket_index in self.bounce_order(id) {
          let entries = self.buckets[bucket_index].entries.read().unwrap();
          if entries.is_empty() {
             continue;
          }
-         
-         let mut nodes_from_bucket = entries.clone().into_iter().collect::<Vec<NodeInfo>>();
+
+         let mut nodes_from_bucket = entries.iter().map(|entry| entry.info.clone()).collect::<Vec<NodeInfo>>();
          if let Some(blacklist) = blacklist {
             nodes_from_bucket.retain(|node: &NodeInfo| !blacklist.contains(&node.id));
          }
@@ -159,6 +236,83 @@ impl Table {
       closest
    }
 
+   /// Reliability-biased version of `closest_n_nodes_to`, borrowed from
+   /// Veilid's `find_preferred_closest_nodes`. Walks the table in the same
+   /// bounce order, but splits candidates into two tiers: nodes classified
+   /// "reliable" (seen within `RELIABILITY_RECENCY_MINS` and with a success
+   /// ratio of at least `RELIABILITY_SUCCESS_RATIO`), each tier internally
+   /// sorted by XOR distance as before, followed by "unreliable" nodes used
+   /// only to fill whatever slots the reliable tier left empty.
+   pub fn find_preferred_closest_nodes(&self, id: &Hash, n: usize, blacklist: Option<&Vec<Hash>>) -> Vec<NodeInfo> {
+      let mut reliable   = Vec::with_capacity(n);
+      let mut unreliable = Vec::with_capacity(n);
+
+      for bucket_index in self.bounce_order(id) {
+         let entries = self.buckets[bucket_index].entries.read().unwrap();
+         if entries.is_empty() {
+            continue;
+         }
+
+         let mut from_bucket = entries.clone().into_iter().collect::<Vec<BucketEntry>>();
+         if let Some(blacklist) = blacklist {
+            from_bucket.retain(|entry| !blacklist.contains(&entry.info.id));
+         }
+         from_bucket.sort_by_key(|entry| &entry.info.id ^ id);
+
+         for entry in from_bucket {
+            if entry.liveness.is_reliable() {
+               if reliable.len() < n {
+                  reliable.push(entry.info);
+               }
+            } else if unreliable.len() < n {
+               unreliable.push(entry.info);
+            }
+         }
+
+         if reliable.len() >= n {
+            break;
+         }
+      }
+
+      let space_left = n - reliable.len();
+      unreliable.truncate(space_left);
+      reliable.extend(unreliable);
+      reliable
+   }
+
+   /// Marks a node as having just answered an RPC successfully, refreshing
+   /// its last-seen time and success counter so it can be picked up by
+   /// `find_preferred_closest_nodes`.
+   pub fn record_success(&self, id: &Hash) {
+      self.touch_liveness(id, |liveness| {
+         liveness.last_seen = time::SteadyTime::now();
+         liveness.successes += 1;
+      });
+   }
+
+   /// Marks a node as having timed out on an RPC.
+   pub fn record_failure(&self, id: &Hash) {
+      self.touch_liveness(id, |liveness| liveness.failures += 1);
+   }
+
+   /// The order in which buckets are visited by the bounce lookup algorithm,
+   /// shared by `closest_n_nodes_to` and `find_preferred_closest_nodes`.
+   fn bounce_order(&self, id: &Hash) -> Box<Iterator<Item = usize>> {
+      let distance = &self.parent_id ^ id;
+      let descent  = distance.ones().rev();
+      let ascent   = distance.zeroes();
+      Box::new(descent.chain(ascent))
+   }
+
+   fn touch_liveness<F>(&self, id: &Hash, f: F) where F: FnOnce(&mut Liveness) {
+      if let Some(index) = self.bucket_for_node(id) {
+         let mut entries = self.buckets[index].entries.write().unwrap();
+         if let Some(entry) = entries.iter_mut().find(|entry| entry.info.id == *id) {
+            f(&mut entry.liveness);
+         }
+      }
+   }
+
    /// Returns the appropriate position for a node, by computing
    /// the index where their prefix starts differing. If we are requesting
    /// the bucket for this table's own parent node, it can't be stored.
@@ -166,13 +320,6 @@ impl Table {
        (&self.parent_id ^ id).height()
    }
 
-   fn revert_conflict(&self, conflict: EvictionConflict) {
-      if let Some(index) = self.bucket_for_node(&conflict.inserted.id) {
-         let mut entries = self.buckets[index].entries.write().unwrap();
-         let evictor = &mut entries.iter_mut().find(|ref info| conflict.inserted.id == info.id).unwrap();
-         mem::replace::<NodeInfo>(evictor, conflict.evicted);
-      }
-   }
 }
 
 /// Produces copies of all known nodes, ordered in ascending
@@ -184,22 +331,67 @@ pub struct AllNodes<'a> {
    bucket_index   : usize,
 }
 
-/// Represents a conflict derived from attempting to insert a node in a full
-/// bucket. 
-#[derive(Debug,Clone)]
-struct EvictionConflict {
-   evicted  : NodeInfo,
-   inserted : NodeInfo
-}
-
 /// Bucket size is estimated to be small enough not to warrant
 /// the downsides of using a linked list.
 ///
-/// Each vector of bucket entries is protected under its own mutex, to guarantee 
-/// concurrent access to the table.
+/// Each vector of bucket entries is protected under its own mutex, to guarantee
+/// concurrent access to the table. `pending` holds a newcomer that arrived
+/// while the bucket was full, alongside the deadline by which the RPC layer
+/// must confirm or deny the least-recently-seen node's aliveness.
+///
+/// Whenever a method needs both locks, it must acquire `pending` before
+/// `entries`, to avoid an AB-BA deadlock between concurrent callers.
 #[derive(Debug)]
 struct Bucket {
-   entries: RwLock<VecDeque<NodeInfo>>,
+   entries : RwLock<VecDeque<BucketEntry>>,
+   pending : Mutex<Option<(NodeInfo, time::SteadyTime)>>,
+}
+
+/// A node stored in a bucket, together with the liveness metadata used to
+/// bias lookups towards nodes likely to answer.
+#[derive(Debug, Clone)]
+struct BucketEntry {
+   info     : NodeInfo,
+   liveness : Liveness,
+}
+
+/// Liveness metadata for a bucket entry: when it was last confirmed alive,
+/// and how often it has answered versus timed out since.
+#[derive(Debug, Clone)]
+struct Liveness {
+   last_seen : time::SteadyTime,
+   successes : u32,
+   failures  : u32,
+}
+
+impl BucketEntry {
+   fn new(info: NodeInfo) -> BucketEntry {
+      BucketEntry {
+         info     : info,
+         liveness : Liveness::new(),
+      }
+   }
+}
+
+impl Liveness {
+   fn new() -> Liveness {
+      Liveness {
+         last_seen : time::SteadyTime::now(),
+         successes : 0,
+         failures  : 0,
+      }
+   }
+
+   /// A node is reliable if it has been seen recently, and (once it has any
+   /// RPC history) answers often enough. Freshly inserted nodes with no
+   /// history yet are given the benefit of the doubt as long as they're
+   /// recent.
+   fn is_reliable(&self) -> bool {
+      let recent = time::SteadyTime::now() - self.last_seen < time::Duration::minutes(RELIABILITY_RECENCY_MINS);
+      let total  = self.successes + self.failures;
+      let answers_enough = total == 0 || (self.successes as f64 / total as f64) >= RELIABILITY_SUCCESS_RATIO;
+      recent && answers_enough
+   }
 }
 
 impl<'a> Iterator for AllNodes<'a> {
@@ -209,20 +401,21 @@ impl<'a> Iterator for AllNodes<'a> {
       while self.bucket_index < HASH_SIZE && self.current_bucket.is_empty() {
          let mut new_bucket = { // Lock scope
             self.table.buckets[self.bucket_index].entries.read().unwrap().clone()
-         }.into_iter().collect::<Vec<NodeInfo>>();
+         }.into_iter().map(|entry| entry.info).collect::<Vec<NodeInfo>>();
 
          new_bucket.sort_by_key(|ref info| &info.id ^ &self.table.parent_id);
          self.current_bucket.append(&mut new_bucket);
          self.bucket_index += 1;
       }
       self.current_bucket.pop()
-   } 
+   }
 }
 
 impl Bucket {
    fn new() -> Bucket {
-      Bucket{
-         entries: RwLock::new(VecDeque::with_capacity(BUCKET_DEPTH))
+      Bucket {
+         entries : RwLock::new(VecDeque::with_capacity(BUCKET_DEPTH)),
+         pending : Mutex::new(None),
       }
    }
 }
@@ -1,6 +1,10 @@
+use bincode;
 use time;
 use hash::SubotaiHash;
+use std::cmp;
+use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 use std::sync::RwLock;
 
 pub const MAX_STORAGE: usize = 10000;
@@ -10,16 +14,60 @@ pub const MAX_STORAGE: usize = 10000;
 const BASE_EXPIRATION_TIME_HRS : i64 = 24;
 const EXPIRATION_DISTANCE_THRESHOLD : usize = 5;
 
+/// Size of the sliding window the rolling hash is computed over, in bytes.
+const CHUNK_WINDOW_SIZE : usize = 48;
+
+/// A chunk boundary falls wherever the low `CHUNK_SIZE_BITS` bits of the
+/// rolling hash are all zero, giving an average chunk size of
+/// `2^CHUNK_SIZE_BITS` bytes.
+const CHUNK_SIZE_BITS : u32 = 13; // ~8 KiB average chunk size.
+const CHUNK_SIZE_MASK : u64 = (1 << CHUNK_SIZE_BITS) - 1;
+
+/// Hard bounds around the average chunk size, to avoid pathologically small
+/// or large chunks when the content happens to produce runs of boundaries
+/// (or none at all).
+const MIN_CHUNK_SIZE : usize = 1 << (CHUNK_SIZE_BITS - 2);
+const MAX_CHUNK_SIZE : usize = 1 << (CHUNK_SIZE_BITS + 2);
+
+/// Blobs larger than this are split into content-defined chunks and stored
+/// under their own content-addressed keys, so identical chunk content shared
+/// across different keys is only stored once.
+const CHUNKING_THRESHOLD : usize = MAX_CHUNK_SIZE;
+
+/// Interval at which a node should re-announce its own stored entries to the
+/// network, the way Kademlia and Solana periodically re-gossip state, so
+/// values survive expiration at other nodes as the DHT churns.
+pub const REPUBLISH_INTERVAL_HRS : i64 = 1;
+
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub enum StorageEntry {
    Value(SubotaiHash),
    Blob(Vec<u8>),
+   Manifest(Manifest),
+}
+
+/// Describes a large blob as an ordered sequence of content-addressed
+/// chunks, so it can be transparently reassembled by `Storage::get`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+   pub chunks : Vec<SubotaiHash>,
+   pub length : usize,
 }
 
+/// A stored entry, tagged with the wallclock time it was written and its
+/// expiration. The wallclock tag lets two nodes reconcile concurrent writes
+/// to the same key by keeping whichever one happened later, in the style of
+/// Solana's gossip CRDT `Pubkey -> versioned struct` map.
 #[derive(Debug, Clone)]
 struct EntryAndExpiration {
    entry      : StorageEntry,
    expiration : time::SteadyTime,
+   version    : time::Timespec,
+   /// True for a content-addressed chunk stored via `store_chunk`, false
+   /// for anything stored under a caller-chosen key via `store_entry`
+   /// (values and manifests). Lets `entries_to_republish` leave chunks out
+   /// of the entries it returns.
+   is_chunk   : bool,
 }
 
 pub struct Storage {
@@ -29,8 +77,16 @@ pub struct Storage {
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub enum StoreResult {
+   /// The key was empty, and now holds this entry.
    Success,
-   AlreadyPresent,
+   /// The key already held an entry, and this write won the last-writer-wins
+   /// merge (a newer version, or an equal one winning the tiebreak), so it
+   /// now holds this entry instead.
+   Updated,
+   /// The key already held an entry that this write did not beat (a newer
+   /// version, or an equal one winning the tiebreak), so the write was
+   /// discarded and the existing entry kept.
+   Stale,
    StorageFull,
 }
 
@@ -41,7 +97,7 @@ impl Storage {
          parent_id               : parent_id,
       }
    }
-   
+
    pub fn len(&self) -> usize {
       self.entries_and_expirations.read().unwrap().len()
    }
@@ -50,33 +106,297 @@ impl Storage {
       self.entries_and_expirations.read().unwrap().is_empty()
    }
 
+   /// Stores a value under a key, stamped with the current wallclock as its
+   /// version. Blobs larger than `CHUNKING_THRESHOLD` are transparently
+   /// split into content-defined chunks, each stored under its own content
+   /// hash, with a manifest stored under `key` to tie them back together.
    pub fn store(&self, key: SubotaiHash, entry: StorageEntry) -> StoreResult {
+      self.store_versioned(key, entry, time::get_time())
+   }
+
+   /// Like `store`, but the caller supplies the version rather than having
+   /// it stamped with the local wallclock. Used to apply a value received
+   /// from elsewhere in the network (a remote store RPC, or a republished
+   /// entry) under the version its origin gave it, so the last-writer-wins
+   /// merge in `store_entry` depends on when the value was produced rather
+   /// than when it last passed through a node.
+   pub fn store_versioned(&self, key: SubotaiHash, entry: StorageEntry, version: time::Timespec) -> StoreResult {
+      match entry {
+         StorageEntry::Blob(bytes) => {
+            if bytes.len() > CHUNKING_THRESHOLD {
+               self.store_chunked_blob(key, bytes, version)
+            } else {
+               self.store_entry(key, StorageEntry::Blob(bytes), version)
+            }
+         },
+         entry => self.store_entry(key, entry, version),
+      }
+   }
+
+   pub fn get(&self, key: &SubotaiHash) -> Option<StorageEntry> {
+      match self.get_entry(key) {
+         Some(StorageEntry::Manifest(manifest)) => self.reassemble(&manifest),
+         other => other,
+      }
+   }
+
+   /// Splits `bytes` along content-defined boundaries, stores each chunk
+   /// under its own content hash (deduplicating chunks shared with any
+   /// other stored value), and stores the resulting manifest under `key`.
+   ///
+   /// Chunks are stored with the manifest's own expiration rather than one
+   /// derived from their content hash's (essentially random) distance, so a
+   /// chunk never expires out from under a manifest that still needs it. A
+   /// chunk shared by several manifests keeps whichever expiration is
+   /// furthest out, via `store_chunk`.
+   fn store_chunked_blob(&self, key: SubotaiHash, bytes: Vec<u8>, version: time::Timespec) -> StoreResult {
+      let length = bytes.len();
+      let boundaries = chunk_boundaries(&bytes);
+
+      let mut chunk_hashes = Vec::with_capacity(boundaries.len());
+      let mut chunk_start = 0;
+      for &boundary in &boundaries {
+         chunk_hashes.push(SubotaiHash::hash(&bytes[chunk_start..boundary]));
+         chunk_start = boundary;
+      }
+      let manifest = StorageEntry::Manifest(Manifest { chunks: chunk_hashes, length: length });
+
+      // Decide the manifest's last-writer-wins outcome before writing a
+      // single chunk. Otherwise a stale write (one `store_entry` below
+      // would reject anyway) would first leave every one of its chunks
+      // sitting in storage with no manifest left referencing them.
+      if !self.would_store(&key, &manifest, version) {
+         return StoreResult::Stale;
+      }
+
+      let expiration = self.expiration_for(&key);
+      let mut chunk_start = 0;
+      for &boundary in &boundaries {
+         let chunk = &bytes[chunk_start..boundary];
+         let chunk_hash = SubotaiHash::hash(chunk);
+         // If a chunk can't be persisted (e.g. the store is full), bail out
+         // rather than going on to write a manifest that would reference a
+         // chunk we never actually stored.
+         match self.store_chunk(chunk_hash, chunk.to_vec(), expiration) {
+            StoreResult::Success | StoreResult::Updated => (),
+            failure => return failure,
+         }
+         chunk_start = boundary;
+      }
+
+      self.store_entry(key, manifest, version)
+   }
+
+   /// Reports whether storing `candidate` under `version` would actually
+   /// take effect: true if the key is empty, or if it would win the
+   /// last-writer-wins merge `store_entry` applies. Lets
+   /// `store_chunked_blob` find out a stale write is doomed before it
+   /// writes any chunks for it.
+   fn would_store(&self, key: &SubotaiHash, candidate: &StorageEntry, version: time::Timespec) -> bool {
+      let entries_and_expirations = self.entries_and_expirations.read().unwrap();
+      match entries_and_expirations.get(key) {
+         None => true,
+         Some(incumbent) => {
+            let candidate = EntryAndExpiration { entry: candidate.clone(), expiration: incumbent.expiration, version: version, is_chunk: false };
+            Storage::wins(&candidate, incumbent)
+         },
+      }
+   }
+
+   /// Stores a content-addressed chunk directly, bypassing the
+   /// last-writer-wins merge `store_entry` uses for mutable keys: since the
+   /// key is the hash of the content, two stores under the same key always
+   /// carry identical bytes, so there's nothing to reconcile beyond keeping
+   /// the entry alive for as long as the longest-lived manifest that
+   /// references it.
+   fn store_chunk(&self, key: SubotaiHash, bytes: Vec<u8>, expiration: time::SteadyTime) -> StoreResult {
       let mut entries_and_expirations = self.entries_and_expirations.write().unwrap();
-      let expiration = time::SteadyTime::now() + time::Duration::hours(BASE_EXPIRATION_TIME_HRS);
 
-      let entry_and_expiration = EntryAndExpiration { entry: entry, expiration: expiration, };
-      if entries_and_expirations.len() >= MAX_STORAGE {
-         StoreResult::StorageFull
-      } else {
-         match entries_and_expirations.insert(key, entry_and_expiration) {
-            None    => StoreResult::Success,
-            Some(_) => StoreResult::AlreadyPresent,
+      if !entries_and_expirations.contains_key(&key) && entries_and_expirations.len() >= MAX_STORAGE {
+         return StoreResult::StorageFull;
+      }
+
+      match entries_and_expirations.entry(key) {
+         Entry::Vacant(vacant) => {
+            vacant.insert(EntryAndExpiration { entry: StorageEntry::Blob(bytes), expiration: expiration, version: time::get_time(), is_chunk: true });
+            StoreResult::Success
+         },
+         Entry::Occupied(mut occupied) => {
+            if expiration > occupied.get().expiration {
+               occupied.get_mut().expiration = expiration;
+            }
+            StoreResult::Updated
+         },
+      }
+   }
+
+   /// Fetches every chunk listed in a manifest and concatenates them back
+   /// into the original blob. Returns `None` if any chunk is missing.
+   fn reassemble(&self, manifest: &Manifest) -> Option<StorageEntry> {
+      let mut bytes = Vec::with_capacity(manifest.length);
+      for chunk_hash in &manifest.chunks {
+         match self.get_entry(chunk_hash) {
+            Some(StorageEntry::Blob(chunk)) => bytes.extend(chunk),
+            _ => return None,
          }
       }
+      Some(StorageEntry::Blob(bytes))
    }
 
-   pub fn get(&self, key: &SubotaiHash) -> Option<StorageEntry> {
-      if let Some( &EntryAndExpiration { ref entry, .. } ) = self.entries_and_expirations.read().unwrap().get(key) {
-         Some(entry.clone())
+   /// Drops every entry whose expiration date has passed. Intended to be
+   /// run periodically by a reaper task.
+   pub fn purge_expired(&self) {
+      let now = time::SteadyTime::now();
+      self.entries_and_expirations.write().unwrap().retain(|_, entry_and_expiration| entry_and_expiration.expiration > now);
+   }
+
+   /// Returns every currently unexpired value or manifest entry, its key
+   /// and its original version, for the node's RPC layer to re-announce on
+   /// a `REPUBLISH_INTERVAL_HRS` cycle. The version must travel with the
+   /// entry and be re-applied via `store_versioned` rather than `store`,
+   /// so a republished value is reconciled by when it was produced, not by
+   /// the time of the republish.
+   ///
+   /// Content-addressed chunks are left out, including the chunks of the
+   /// manifests it returns: a node that receives a republished manifest
+   /// can only reassemble it from chunks it already holds, and otherwise
+   /// `get` there returns `None` for that key.
+   pub fn entries_to_republish(&self) -> Vec<(SubotaiHash, StorageEntry, time::Timespec)> {
+      let now = time::SteadyTime::now();
+      self.entries_and_expirations
+         .read()
+         .unwrap()
+         .iter()
+         .filter(|&(_, entry_and_expiration)| entry_and_expiration.expiration > now && !entry_and_expiration.is_chunk)
+         .map(|(key, entry_and_expiration)| (key.clone(), entry_and_expiration.entry.clone(), entry_and_expiration.version))
+         .collect()
+   }
+
+   /// Computes how long a key should be cached for, based on its distance
+   /// from this node. Expiration stays at `BASE_EXPIRATION_TIME_HRS` up to
+   /// `EXPIRATION_DISTANCE_THRESHOLD`, then decays exponentially, so keys
+   /// far from this node (and therefore unlikely to be looked up through
+   /// it) aren't cached for long.
+   fn expiration_for(&self, key: &SubotaiHash) -> time::SteadyTime {
+      let height = (&self.parent_id ^ key).height().unwrap_or(0);
+      let hours = if height <= EXPIRATION_DISTANCE_THRESHOLD {
+         BASE_EXPIRATION_TIME_HRS
       } else {
-         None
+         // A right shift, not `2.pow(shift)`, since the shift can be as
+         // large as HASH_SIZE and an unbounded `pow` would overflow `i64`
+         // (and then divide-by-zero) long before the shift itself would:
+         // `>>` just saturates to 0, which `cmp::max(_, 1)` floors to 1h.
+         let shift = cmp::min((height - EXPIRATION_DISTANCE_THRESHOLD) as u32, 63);
+         cmp::max(BASE_EXPIRATION_TIME_HRS >> shift, 1)
+      };
+      time::SteadyTime::now() + time::Duration::hours(hours)
+   }
+
+   fn store_entry(&self, key: SubotaiHash, entry: StorageEntry, version: time::Timespec) -> StoreResult {
+      let mut entries_and_expirations = self.entries_and_expirations.write().unwrap();
+      let expiration = self.expiration_for(&key);
+      let candidate = EntryAndExpiration { entry: entry, expiration: expiration, version: version, is_chunk: false };
+
+      if !entries_and_expirations.contains_key(&key) && entries_and_expirations.len() >= MAX_STORAGE {
+         return StoreResult::StorageFull;
+      }
+
+      match entries_and_expirations.entry(key) {
+         Entry::Vacant(vacant) => {
+            vacant.insert(candidate);
+            StoreResult::Success
+         },
+         Entry::Occupied(mut occupied) => {
+            if Storage::wins(&candidate, occupied.get()) {
+               occupied.insert(candidate);
+               StoreResult::Updated
+            } else {
+               StoreResult::Stale
+            }
+         },
+      }
+   }
+
+   /// Decides whether `candidate` should replace `incumbent` in a
+   /// last-writer-wins merge: the higher version wins, with ties (same
+   /// wallclock instant) broken deterministically by comparing the
+   /// entries' serialized bytes, so every node reconciles concurrent writes
+   /// to the same conclusion.
+   fn wins(candidate: &EntryAndExpiration, incumbent: &EntryAndExpiration) -> bool {
+      match candidate.version.cmp(&incumbent.version) {
+         Ordering::Greater => true,
+         Ordering::Less    => false,
+         Ordering::Equal   => serialized(&candidate.entry) > serialized(&incumbent.entry),
       }
    }
+
+   fn get_entry(&self, key: &SubotaiHash) -> Option<StorageEntry> {
+      let now = time::SteadyTime::now();
+      if let Some( &EntryAndExpiration { ref entry, expiration, .. } ) = self.entries_and_expirations.read().unwrap().get(key) {
+         if expiration > now {
+            return Some(entry.clone());
+         }
+      }
+      None
+   }
+}
+
+/// Returns the byte offsets (relative to the start of `bytes`) where each
+/// content-defined chunk ends, the last one always being `bytes.len()`.
+/// Boundaries are found with a polynomial rolling hash over a sliding
+/// window of `CHUNK_WINDOW_SIZE` bytes: a boundary falls wherever the bits
+/// of the hash under `CHUNK_SIZE_MASK` are all zero, which keeps boundaries stable
+/// under insertions or edits elsewhere in the blob, since they depend only
+/// on local content.
+fn chunk_boundaries(bytes: &[u8]) -> Vec<usize> {
+   const BASE: u64 = 257;
+
+   if bytes.len() <= MIN_CHUNK_SIZE {
+      return vec![bytes.len()];
+   }
+
+   let mut drop_multiplier: u64 = 1;
+   for _ in 1..CHUNK_WINDOW_SIZE {
+      drop_multiplier = drop_multiplier.wrapping_mul(BASE);
+   }
+
+   let mut boundaries = Vec::new();
+   let mut chunk_start = 0;
+   let mut hash: u64 = 0;
+
+   for (i, &byte) in bytes.iter().enumerate() {
+      hash = hash.wrapping_mul(BASE).wrapping_add(byte as u64);
+      if i >= chunk_start + CHUNK_WINDOW_SIZE {
+         let leaving = bytes[i - CHUNK_WINDOW_SIZE];
+         hash = hash.wrapping_sub((leaving as u64).wrapping_mul(drop_multiplier).wrapping_mul(BASE));
+      }
+
+      let chunk_len = i + 1 - chunk_start;
+      let at_boundary = chunk_len >= MIN_CHUNK_SIZE && (hash & CHUNK_SIZE_MASK) == 0;
+      if at_boundary || chunk_len == MAX_CHUNK_SIZE {
+         boundaries.push(i + 1);
+         chunk_start = i + 1;
+         hash = 0;
+      }
+   }
+
+   if chunk_start < bytes.len() {
+      boundaries.push(bytes.len());
+   }
+   boundaries
+}
+
+/// Returns the bincode-serialized bytes of `entry`, used as the
+/// deterministic tiebreak in `Storage::wins` when two versions are equal:
+/// comparing the actual wire representation avoids the cost and fragility
+/// of formatting a debug string just to compare it.
+fn serialized(entry: &StorageEntry) -> Vec<u8> {
+   bincode::serialize(entry).unwrap_or_default()
 }
 
 #[cfg(test)]
 mod tests {
-   use super::*; 
+   use super::*;
    use storage;
    use hash;
    use time;
@@ -94,7 +414,7 @@ mod tests {
 
       storage.store(key_at_1.clone(), dummy_entry.clone());
       storage.store(key_at_expf.clone(), dummy_entry.clone());
-      
+
       // Both keys should have an expiration date of roughly 24 hours from now.
       let exp_alpha = storage.entries_and_expirations.read().unwrap().get(&key_at_1).unwrap().expiration.clone();
       let exp_beta  = storage.entries_and_expirations.read().unwrap().get(&key_at_expf).unwrap().expiration.clone();
@@ -108,7 +428,91 @@ mod tests {
       assert!(exp_beta  >= time::SteadyTime::now() + min_duration);
    }
 
-}
+   #[test]
+   fn expiration_date_calculation_far_beyond_distance_threshold() {
+      let id = hash::SubotaiHash::random();
+      let storage = Storage::new(id.clone());
+
+      // A height this far past `EXPIRATION_DISTANCE_THRESHOLD` would overflow
+      // `2i64.pow(height - threshold)` and then panic on a divide by the
+      // resulting zero; it should instead just floor to the 1h minimum.
+      let far_key = hash::SubotaiHash::random_at_distance(&id, 70);
+      let dummy_entry = StorageEntry::Value(hash::SubotaiHash::random());
+
+      storage.store(far_key.clone(), dummy_entry);
+
+      let expiration = storage.entries_and_expirations.read().unwrap().get(&far_key).unwrap().expiration.clone();
+      assert!(expiration <= time::SteadyTime::now() + time::Duration::hours(1));
+   }
+
+   #[test]
+   fn chunks_and_reassembles_large_blobs() {
+      let id = hash::SubotaiHash::random();
+      let storage = Storage::new(id.clone());
+      let key = hash::SubotaiHash::random();
 
+      let mut blob = Vec::with_capacity(super::CHUNKING_THRESHOLD * 2);
+      for i in 0..blob.capacity() {
+         blob.push((i % 251) as u8);
+      }
+
+      storage.store(key.clone(), StorageEntry::Blob(blob.clone()));
+      match storage.get(&key) {
+         Some(StorageEntry::Blob(retrieved)) => assert_eq!(retrieved, blob),
+         other => panic!("expected a reassembled blob, got {:?}", other),
+      }
+   }
+
+   #[test]
+   fn dedupes_identical_chunk_content_across_keys() {
+      let id = hash::SubotaiHash::random();
+      let storage = Storage::new(id.clone());
+
+      let mut blob = Vec::with_capacity(super::CHUNKING_THRESHOLD * 2);
+      for i in 0..blob.capacity() {
+         blob.push((i % 251) as u8);
+      }
+
+      storage.store(hash::SubotaiHash::random(), StorageEntry::Blob(blob.clone()));
+      let after_first = storage.len();
+      storage.store(hash::SubotaiHash::random(), StorageEntry::Blob(blob.clone()));
+      let after_second = storage.len();
+
+      // The second store only adds a new manifest; every chunk it
+      // references was already stored by the first.
+      assert_eq!(after_second - after_first, 1);
+   }
+
+   #[test]
+   fn second_store_with_a_newer_version_wins() {
+      let id = hash::SubotaiHash::random();
+      let storage = Storage::new(id.clone());
+      let key = hash::SubotaiHash::random();
+
+      let first = StorageEntry::Value(hash::SubotaiHash::random());
+      let second = StorageEntry::Value(hash::SubotaiHash::random());
+
+      assert_eq!(storage.store(key.clone(), first), StoreResult::Success);
+      assert_eq!(storage.store(key.clone(), second.clone()), StoreResult::Updated);
+      assert_eq!(storage.get(&key), Some(second));
+   }
+
+   #[test]
+   fn expired_entries_are_treated_as_absent_and_purged() {
+      let id = hash::SubotaiHash::random();
+      let storage = Storage::new(id.clone());
+      let key = hash::SubotaiHash::random();
 
+      storage.store(key.clone(), StorageEntry::Value(hash::SubotaiHash::random()));
+      assert!(storage.get(&key).is_some());
 
+      {
+         let mut entries_and_expirations = storage.entries_and_expirations.write().unwrap();
+         entries_and_expirations.get_mut(&key).unwrap().expiration = time::SteadyTime::now() - time::Duration::seconds(1);
+      }
+
+      assert!(storage.get(&key).is_none());
+      storage.purge_expired();
+      assert!(storage.is_empty());
+   }
+}